@@ -0,0 +1,182 @@
+//! The proc-macro half of `async-stream`: turns a `stream! { ... }` block
+//! containing `yield` expressions into a call to
+//! [`AsyncStream::new`][new], the way [`futures-async-stream`][fas]'s
+//! `#[stream]` attribute turns a `yield`-using function into a generator.
+//!
+//! This crate is not meant to be used directly; depend on `async-stream`
+//! with the `macros` feature enabled and use `async_stream::stream!`
+//! instead.
+//!
+//! [new]: https://docs.rs/async-stream/*/async_stream/struct.AsyncStream.html#method.new
+//! [fas]: https://docs.rs/futures-async-stream
+
+extern crate proc_macro;
+
+use proc_macro2::{Delimiter, Ident, TokenStream, TokenTree};
+use quote::quote;
+use syn::fold::Fold;
+use syn::{parse2, parse_quote, Block, Expr, ExprYield};
+
+/// `stream! { ... }` lets the body of the block `yield` items instead of
+/// calling `sender.send(item).await` by hand, and propagate errors with
+/// `?` instead of matching on a `Result` and sending it manually.
+///
+/// ```ignore
+/// let strm = async_stream::stream! {
+///     for i in 0u8..10 {
+///         yield i;
+///     }
+/// };
+/// ```
+///
+/// expands to (roughly):
+///
+/// ```ignore
+/// let strm = async_stream::AsyncStream::new(move |mut __sender| async move {
+///     for i in 0u8..10 {
+///         __sender.send(i).await;
+///     }
+///     Ok(())
+/// });
+/// ```
+///
+/// A `for await x in substream { ... }` loop is also supported as sugar
+/// for draining a sub-[`Stream`] item by item; the common case of simply
+/// forwarding every item (`for await x in substream { yield x; }`)
+/// expands straight to [`Sender::send_all`], except inside a nested
+/// `async` block or closure.
+///
+/// [`Stream`]: https://docs.rs/futures/*/futures/stream/trait.Stream.html
+/// [`Sender::send_all`]: https://docs.rs/async-stream/*/async_stream/struct.Sender.html#method.send_all
+#[proc_macro]
+pub fn stream(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = expand_for_await(input.into(), false);
+    let block: Block =
+        parse2(quote! { { #input } }).expect("stream!: expected a block of statements");
+    let block = YieldFolder.fold_block(block);
+
+    let expanded = quote! {
+        ::async_stream::AsyncStream::new(move |mut __sender| async move {
+            #block
+            Ok(())
+        })
+    };
+    expanded.into()
+}
+
+/// Rewrites every `yield EXPR` in the block into `__sender.send(EXPR).await`,
+/// without touching `yield`s that belong to a *different* generator
+/// nested inside the block (another `async` block/closure), since those
+/// are not ours to rewrite.
+struct YieldFolder;
+
+impl Fold for YieldFolder {
+    fn fold_expr(&mut self, expr: Expr) -> Expr {
+        match expr {
+            Expr::Yield(ExprYield { expr, .. }) => {
+                let expr = expr.map(|e| Box::new(self.fold_expr(*e)));
+                let sent = match expr {
+                    Some(e) => e,
+                    None => Box::new(parse_quote!(())),
+                };
+                parse_quote! { __sender.send(#sent).await }
+            }
+            // Don't recurse into nested async blocks/closures: any
+            // `yield` inside them belongs to that generator, not this
+            // one.
+            Expr::Async(e) => Expr::Async(e),
+            Expr::Closure(e) => Expr::Closure(e),
+            other => syn::fold::fold_expr(self, other),
+        }
+    }
+}
+
+fn is_ident(tt: &TokenTree, s: &str) -> bool {
+    matches!(tt, TokenTree::Ident(i) if i == s)
+}
+
+/// Expands `for await PAT in EXPR { BODY }` into a plain `while let` loop
+/// that pulls `EXPR` one item at a time, before the block is handed to
+/// [`syn`] for parsing (`for await ...` is not valid Rust syntax on its
+/// own). The trivial "forward everything" body, `yield PAT;`, is
+/// special-cased to expand straight into [`Sender::send_all`] instead of
+/// a hand-written loop, unless `nested` is set: inside another `async`
+/// block or closure the loop form is used, so its `yield` is left to
+/// that generator.
+fn expand_for_await(input: TokenStream, nested: bool) -> TokenStream {
+    let mut out = Vec::new();
+    let mut iter = input.into_iter().peekable();
+    while let Some(tt) = iter.next() {
+        if is_ident(&tt, "for") && iter.peek().is_some_and(|n| is_ident(n, "await")) {
+            iter.next(); // consume `await`
+
+            let mut pat_tokens = Vec::new();
+            loop {
+                let t = iter.next().expect("stream!: `for await` expects `in`");
+                if is_ident(&t, "in") {
+                    break;
+                }
+                pat_tokens.push(t);
+            }
+
+            let mut expr_tokens = Vec::new();
+            let body = loop {
+                let t = iter
+                    .next()
+                    .expect("stream!: `for await` expects a block body");
+                if let TokenTree::Group(g) = &t {
+                    if g.delimiter() == Delimiter::Brace {
+                        break g.clone();
+                    }
+                }
+                expr_tokens.push(t);
+            };
+
+            let pat_ts: TokenStream = pat_tokens.into_iter().collect();
+            let expr_ts: TokenStream = expr_tokens.into_iter().collect();
+            let body_ts = expand_for_await(body.stream(), nested);
+
+            let is_plain_forward = match parse2::<Ident>(pat_ts.clone()) {
+                Ok(ident) => {
+                    !nested && body_ts.to_string() == quote!(yield #ident ;).to_string()
+                }
+                Err(_) => false,
+            };
+
+            if is_plain_forward {
+                out.extend(quote! { __sender.send_all(#expr_ts).await?; });
+            } else {
+                out.extend(quote! {
+                    {
+                        let mut __async_stream_substream = #expr_ts;
+                        while let ::core::option::Option::Some(#pat_ts) =
+                            ::futures::StreamExt::next(&mut __async_stream_substream).await
+                        {
+                            #body_ts
+                        }
+                    }
+                });
+            }
+            continue;
+        }
+
+        match tt {
+            TokenTree::Group(g) => {
+                // A brace group right after `async`, `move` or a closure's
+                // `|...|` is the body of another generator or closure.
+                let other_body = g.delimiter() == Delimiter::Brace
+                    && out.last().is_some_and(|prev| {
+                        is_ident(prev, "async")
+                            || is_ident(prev, "move")
+                            || matches!(prev, TokenTree::Punct(p) if p.as_char() == '|')
+                    });
+                let inner = expand_for_await(g.stream(), nested || other_body);
+                let mut new_group = proc_macro2::Group::new(g.delimiter(), inner);
+                new_group.set_span(g.span());
+                out.push(TokenTree::Group(new_group));
+            }
+            other => out.push(other),
+        }
+    }
+    out.into_iter().collect()
+}
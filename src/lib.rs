@@ -34,58 +34,184 @@
 //!
 //! On success the async closure should return `Ok(())`.
 //!
+//! With the `macros` feature enabled, the [`stream!`] macro lets the
+//! closure above be written with `yield` instead of `y.send(..).await`:
+//!
+//! ```rust,ignore
+//! // Only compiles with the `macros` feature enabled; see the
+//! // `stream_macro_supports_yield_and_for_await` test for a version of
+//! // this that's actually run.
+//! let strm: async_stream::AsyncStream<u8, std::io::Error> = async_stream::stream! {
+//!     for i in 0u8..10 {
+//!         yield i;
+//!     }
+//! };
+//! ```
+//!
 //! [async]: https://rust-lang.github.io/async-book/getting_started/async_await_primer.html
 //! [Stream01]: https://docs.rs/futures/0.1/futures/stream/trait.Stream.html
 //! [Stream03]: https://rust-lang-nursery.github.io/futures-api-docs/0.3.0-alpha.16/futures/stream/trait.Stream.html
 //! [send]: async_stream/struct.Sender.html#method.send
+//! [`stream!`]: macro.stream.html
 //!
-use std::cell::Cell;
+// So the `stream!` macro's `::async_stream::...` expansion also resolves
+// when the macro is used from this crate's own tests.
+#[cfg(all(test, feature = "macros"))]
+extern crate self as async_stream;
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
 use std::marker::PhantomData;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
+use futures::task::AtomicWaker;
 use futures::task::Context;
 use futures::task::Poll as Poll03;
 use futures::Future as Future03;
 use futures::Stream as Stream03;
+use futures::StreamExt as StreamExt03;
 
-/// Future returned by the Sender.send() method.
+/// The default queue capacity used by [`AsyncStream::new`][new].
+///
+/// This keeps the one-item buffer of previous versions of this crate;
+/// unlike those versions, a second `send` waits for the first item to be
+/// read instead of overwriting it.
 ///
-/// Completes when the item is sent.
+/// [new]: struct.AsyncStream.html#method.new
+const DEFAULT_CAPACITY: usize = 1;
+
+// Only internally used by one AsyncStream and never shared
+// in any other way, so we don't have to use Arc<Mutex<..>>.
+struct Queue<I> {
+    items: RefCell<VecDeque<I>>,
+    capacity: usize,
+}
+
+impl<I> Queue<I> {
+    fn new(capacity: usize) -> Queue<I> {
+        Queue {
+            items: RefCell::new(VecDeque::with_capacity(capacity)),
+            capacity,
+        }
+    }
+
+    fn has_room(&self) -> bool {
+        self.items.borrow().len() < self.capacity
+    }
+
+    fn push(&self, item: I) {
+        self.items.borrow_mut().push_back(item);
+    }
+
+    fn pop(&self) -> Option<I> {
+        self.items.borrow_mut().pop_front()
+    }
+}
+
+// Shared between an AsyncStream and its AbortHandle. Follows the same
+// shape as futures-util's `Abortable`: a flag the handle sets, plus a
+// waker the stream registers itself with so a parked stream is woken
+// up promptly when aborted.
+struct AbortShared {
+    aborted: AtomicBool,
+    waker: AtomicWaker,
+}
+
+/// A handle that can stop a running [`AsyncStream`] created with
+/// [`AsyncStream::abortable`][abortable].
+///
+/// [abortable]: struct.AsyncStream.html#method.abortable
+pub struct AbortHandle(Arc<AbortShared>);
+
+impl AbortHandle {
+    /// Stop the associated stream.
+    ///
+    /// Its next poll will end the stream (as if the producing future had
+    /// returned) instead of making further progress. Items already
+    /// buffered are still delivered first.
+    ///
+    /// For the futures 0.3 [`Stream`][Stream03] impl, this also wakes a
+    /// stream that was parked waiting on something else, so it notices
+    /// the abort right away. The futures 0.1 [`Stream`][Stream01] impl
+    /// (under the `compat` feature) has no such wakeup path — a 0.1
+    /// consumer parked on `NotReady` only picks up the abort the next
+    /// time something else causes it to be polled again.
+    ///
+    /// [Stream01]: https://docs.rs/futures/0.1/futures/stream/trait.Stream.html
+    /// [Stream03]: https://rust-lang-nursery.github.io/futures-api-docs/0.3.0-alpha.16/futures/stream/trait.Stream.html
+    pub fn abort(&self) {
+        self.0.aborted.store(true, Ordering::SeqCst);
+        self.0.waker.wake();
+    }
+}
+
+/// Future returned by the [Sender::send][send] method.
+///
+/// Completes once the item has been placed on the queue, which may
+/// require waiting for the consumer to free up room.
+///
+/// [send]: struct.Sender.html#method.send
 #[must_use]
-pub struct SenderFuture {
-    is_ready: bool,
+pub struct SenderFuture<I> {
+    queue: Arc<Queue<I>>,
+    item: Option<I>,
 }
 
-impl SenderFuture {
-    fn new() -> SenderFuture {
-        SenderFuture { is_ready: false }
+impl<I> SenderFuture<I> {
+    fn new(queue: Arc<Queue<I>>, item: I) -> SenderFuture<I> {
+        SenderFuture {
+            queue,
+            item: Some(item),
+        }
     }
 }
 
-impl Future03 for SenderFuture {
+// SenderFuture never borrows from itself (the item is just moved out of
+// the Option once there's room), so it's safe to treat it as movable
+// even though `Option<I>` is only unconditionally `Unpin` when `I` is.
+impl<I> Unpin for SenderFuture<I> {}
+
+// The queue it points at is only ever touched through `&Queue`, and the
+// `RefCell` inside is never accessed from more than one thread at a
+// time (the sender and the stream take turns polling), so this mirrors
+// the `unsafe impl Send`/`Sync` on `Sender` itself: without it, a
+// `SenderFuture` held across an `await` point makes the enclosing
+// producer future `!Send`, and `AsyncStream::new` requires `Send`.
+//
+// This is bounded on `I: Send` (unlike `Sender`'s blanket impls) because
+// `item: Option<I>` genuinely moves an `I` across the `await` point this
+// future represents: an unconditional impl would let a `!Send` item
+// (e.g. `Rc<_>`) ride along inside a `Pin<Box<dyn Future + Send>>` and
+// be dropped on the wrong thread.
+unsafe impl<I: Send> Send for SenderFuture<I> {}
+
+impl<I> Future03 for SenderFuture<I> {
     type Output = ();
 
     fn poll(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll03<Self::Output> {
-        if self.is_ready {
+        if self.item.is_none() {
+            return Poll03::Ready(());
+        }
+        if self.queue.has_room() {
+            let item = self.item.take().unwrap();
+            self.queue.push(item);
             Poll03::Ready(())
         } else {
-            self.is_ready = true;
             Poll03::Pending
         }
     }
 }
 
-// Only internally used by one AsyncStream and never shared
-// in any other way, so we don't have to use Arc<Mutex<..>>.
 /// Type of the sender passed as first argument into the async closure.
-pub struct Sender<I, E>(Arc<Cell<Option<I>>>, PhantomData<E>);
+pub struct Sender<I, E>(Arc<Queue<I>>, PhantomData<E>);
 unsafe impl<I, E> Sync for Sender<I, E> {}
 unsafe impl<I, E> Send for Sender<I, E> {}
 
 impl<I, E> Sender<I, E> {
-    fn new(item_opt: Option<I>) -> Sender<I, E> {
-        Sender(Arc::new(Cell::new(item_opt)), PhantomData::<E>)
+    fn new(capacity: usize) -> Sender<I, E> {
+        Sender(Arc::new(Queue::new(capacity)), PhantomData::<E>)
     }
 
     // note that this is NOT impl Clone for Sender, it's private.
@@ -94,15 +220,54 @@ impl<I, E> Sender<I, E> {
     }
 
     /// Send one item to the stream.
-    pub fn send<T>(&mut self, item: T) -> SenderFuture
+    ///
+    /// Returns immediately if there is room in the buffer. Once the
+    /// buffer is full the returned future stays pending until the
+    /// consumer drains an item, giving this method real backpressure
+    /// instead of silently overwriting a previously sent item.
+    pub fn send<T>(&mut self, item: T) -> SenderFuture<I>
     where
         T: Into<I>,
     {
-        self.0.set(Some(item.into()));
-        SenderFuture::new()
+        SenderFuture::new(self.0.clone(), item.into())
+    }
+
+    /// Drive `stream` to completion, forwarding each of its items to this
+    /// stream and stopping at (and propagating) its first error.
+    ///
+    /// Each item is forwarded through [`send`][send], so a sub-stream
+    /// longer than the buffer's capacity will not overflow it: this
+    /// future simply waits for room to free up between items, the same
+    /// way a direct call to `send` would.
+    ///
+    /// [send]: struct.Sender.html#method.send
+    pub fn send_all<S>(&mut self, mut stream: S) -> impl Future03<Output = Result<(), E>>
+    where
+        S: Stream03<Item = Result<I, E>> + Unpin,
+    {
+        let mut sender = self.clone();
+        async move {
+            while let Some(item) = stream.next().await {
+                sender.send(item?).await;
+            }
+            Ok(())
+        }
+    }
+
+    /// Like [`send_all`][send_all], but for a stream that yields items
+    /// directly instead of `Result`s.
+    ///
+    /// [send_all]: struct.Sender.html#method.send_all
+    pub fn send_all_items<S>(&mut self, stream: S) -> impl Future03<Output = Result<(), E>>
+    where
+        S: Stream03<Item = I> + Unpin,
+    {
+        self.send_all(stream.map(Ok))
     }
 }
 
+type ProducerFuture<Error> = dyn Future03<Output = Result<(), Error>> + 'static + Send;
+
 /// An abstraction around a future, where the
 /// future can internally loop and yield items.
 ///
@@ -117,7 +282,13 @@ impl<I, E> Sender<I, E> {
 #[must_use]
 pub struct AsyncStream<Item, Error> {
     item: Sender<Item, Error>,
-    fut: Option<Pin<Box<dyn Future03<Output = Result<(), Error>> + 'static + Send>>>,
+    fut: Option<Pin<Box<ProducerFuture<Error>>>>,
+    abort: Option<Arc<AbortShared>>,
+    // Set once the producing future returns `Err`, but only surfaced once
+    // every item it already queued has been drained (see the `poll`/
+    // `poll_next` impls below): otherwise an item sent just before the
+    // error would be lost or reordered after it.
+    err: Option<Error>,
 }
 
 impl<Item, Error: 'static + Send> AsyncStream<Item, Error> {
@@ -135,27 +306,228 @@ impl<Item, Error: 'static + Send> AsyncStream<Item, Error> {
         R: Future03<Output = Result<(), Error>> + Send + 'static,
         Item: 'static,
     {
-        let sender = Sender::new(None);
+        AsyncStream::with_capacity(DEFAULT_CAPACITY, f)
+    }
+
+    /// Create a new stream like [`new`][new], but buffer up to `capacity`
+    /// items instead of just one.
+    ///
+    /// While there is room in the buffer, `send` resolves right away and
+    /// the async closure can keep running; once the buffer is full,
+    /// `send` will not resolve until the consumer polls an item off the
+    /// front of the buffer. This gives the closure real backpressure
+    /// instead of the single item sent potentially being overwritten
+    /// before it is ever read.
+    ///
+    /// [new]: struct.AsyncStream.html#method.new
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is `0`: a buffer with no room can never free
+    /// up, so the first `send` would hang forever instead of erroring or
+    /// waking up again.
+    pub fn with_capacity<F, R>(capacity: usize, f: F) -> Self
+    where
+        F: FnOnce(Sender<Item, Error>) -> R,
+        R: Future03<Output = Result<(), Error>> + Send + 'static,
+        Item: 'static,
+    {
+        assert!(capacity > 0, "AsyncStream capacity must be at least 1");
+        let sender = Sender::new(capacity);
         AsyncStream::<Item, Error> {
             item: sender.clone(),
             fut: Some(Box::pin(f(sender))),
+            abort: None,
+            err: None,
         }
     }
+
+    /// Create a stream like [`new`][new], paired with a handle that can
+    /// stop it from the outside.
+    ///
+    /// Without this, a long-lived producing future has no cancellation
+    /// point: the only way to stop it is to drop the whole stream. Once
+    /// [`AbortHandle::abort`][abort] is called, the stream ends cleanly
+    /// (as if the producing future had returned) the next time it is
+    /// polled. See [`AbortHandle::abort`][abort] for how promptly that
+    /// happens on a parked stream, which differs between the futures
+    /// 0.1 and 0.3 `Stream` impls.
+    ///
+    /// [new]: struct.AsyncStream.html#method.new
+    /// [abort]: struct.AbortHandle.html#method.abort
+    pub fn abortable<F, R>(f: F) -> (Self, AbortHandle)
+    where
+        F: FnOnce(Sender<Item, Error>) -> R,
+        R: Future03<Output = Result<(), Error>> + Send + 'static,
+        Item: 'static,
+    {
+        let shared = Arc::new(AbortShared {
+            aborted: AtomicBool::new(false),
+            waker: AtomicWaker::new(),
+        });
+        let mut strm = AsyncStream::new(f);
+        strm.abort = Some(shared.clone());
+        (strm, AbortHandle(shared))
+    }
 }
 
 #[cfg(feature = "compat")]
 pub mod compat {
+    use std::pin::Pin;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+
     use futures::compat::Compat as Compat03As01;
+    use futures::task::Context;
+    use futures::task::Poll as Poll03;
+    use futures::task::Waker;
+    use futures::Future as Future03;
+    use futures::Stream as Stream03;
+    use futures01::executor::spawn as spawn01;
+    use futures01::executor::Notify as Notify01;
+    use futures01::executor::NotifyHandle as NotifyHandle01;
+    use futures01::executor::Spawn as Spawn01;
     use futures01::Async as Async01;
     use futures01::Future as Future01;
     use futures01::Stream as Stream01;
 
+    use crate::AsyncStream;
+
+    // Turns a futures 0.3 Waker into something a futures 0.1 executor can
+    // notify: the handle it gets back just wakes the waker it was built
+    // from, same idea as futures-util's `compat01as03` Notify bridge.
+    struct WakerToHandle(Waker);
+
+    impl Notify01 for WakerToHandle {
+        fn notify(&self, _id: usize) {
+            self.0.wake_by_ref();
+        }
+    }
+
+    fn notify_handle(waker: &Waker) -> NotifyHandle01 {
+        NotifyHandle01::from(Arc::new(WakerToHandle(waker.clone())))
+    }
+
+    /// Adapts a futures 0.1 [`Future`][Future01] into a futures 0.3
+    /// [`Future`][Future03].
+    struct FromFuture01<F>(Spawn01<F>);
+
+    impl<F> FromFuture01<F> {
+        fn new(f: F) -> FromFuture01<F> {
+            FromFuture01(spawn01(f))
+        }
+    }
+
+    // `Spawn01<F>` is only ever driven through `&mut self`, never pinned
+    // in the futures 0.1 sense, so moving it around after a poll is fine.
+    impl<F> Unpin for FromFuture01<F> {}
+
+    impl<F> Future03 for FromFuture01<F>
+    where
+        F: Future01,
+    {
+        type Output = Result<F::Item, F::Error>;
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll03<Self::Output> {
+            let handle = notify_handle(cx.waker());
+            match self.get_mut().0.poll_future_notify(&handle, 0) {
+                Ok(Async01::Ready(item)) => Poll03::Ready(Ok(item)),
+                Ok(Async01::NotReady) => Poll03::Pending,
+                Err(e) => Poll03::Ready(Err(e)),
+            }
+        }
+    }
+
+    /// Adapts a futures 0.1 [`Stream`][Stream01] into a futures 0.3
+    /// [`Stream`][Stream03] of `Result`s.
+    struct FromStream01<S>(Spawn01<S>);
+
+    impl<S> FromStream01<S> {
+        fn new(s: S) -> FromStream01<S> {
+            FromStream01(spawn01(s))
+        }
+    }
+
+    impl<S> Unpin for FromStream01<S> {}
+
+    impl<S> Stream03 for FromStream01<S>
+    where
+        S: Stream01,
+    {
+        type Item = Result<S::Item, S::Error>;
+
+        fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll03<Option<Self::Item>> {
+            let handle = notify_handle(cx.waker());
+            match self.get_mut().0.poll_stream_notify(&handle, 0) {
+                Ok(Async01::Ready(Some(item))) => Poll03::Ready(Some(Ok(item))),
+                Ok(Async01::Ready(None)) => Poll03::Ready(None),
+                Ok(Async01::NotReady) => Poll03::Pending,
+                Err(e) => Poll03::Ready(Some(Err(e))),
+            }
+        }
+    }
+
+    impl<Item: 'static, Error: 'static + Send> AsyncStream<Item, Error> {
+        /// Create a stream that yields the single item produced by a
+        /// futures 0.1 [`Future`][Future01], then ends.
+        ///
+        /// This lets a legacy 0.1 producer (old `tokio`, older network
+        /// crates, ...) be lifted into this crate's yielding model.
+        ///
+        /// [Future01]: https://docs.rs/futures/0.1/futures/future/trait.Future.html
+        pub fn from_future01<F>(f: F) -> Self
+        where
+            F: Future01<Item = Item, Error = Error> + Send + 'static,
+            Item: Send,
+        {
+            AsyncStream::new(move |mut y| async move {
+                let item = FromFuture01::new(f).await?;
+                y.send(item).await;
+                Ok(())
+            })
+        }
+
+        /// Create a stream from a futures 0.1 [`Stream`][Stream01],
+        /// forwarding each of its items and ending (or erroring) the same
+        /// way the 0.1 stream does.
+        ///
+        /// [Stream01]: https://docs.rs/futures/0.1/futures/stream/trait.Stream.html
+        pub fn from_stream01<S>(s: S) -> Self
+        where
+            S: Stream01<Item = Item, Error = Error> + Send + 'static,
+            Item: Send,
+        {
+            AsyncStream::new(move |mut y| async move { y.send_all(FromStream01::new(s)).await })
+        }
+    }
+
     /// Stream implementation for Futures 0.1.
     impl<I, E> Stream01 for AsyncStream<I, E> {
         type Item = I;
         type Error = E;
 
         fn poll(&mut self) -> Result<Async01<Option<Self::Item>>, Self::Error> {
+            // Items already sitting in the buffer are drained before the
+            // producing future is polled again; see the futures 0.3
+            // Stream impl below for why.
+            if let Some(item) = self.item.0.pop() {
+                return Ok(Async01::Ready(Some(item)));
+            }
+            // The future already ran to completion (or errored) on an
+            // earlier poll, and its final item, if any, has since been
+            // drained above; surface the error it left behind, if any.
+            if self.fut.is_none() {
+                return match self.err.take() {
+                    Some(e) => Err(e),
+                    None => Ok(Async01::Ready(None)),
+                };
+            }
+            if let Some(abort) = &self.abort {
+                if abort.aborted.load(Ordering::SeqCst) {
+                    self.fut = None;
+                    return Ok(Async01::Ready(None));
+                }
+            }
             // We use a futures::compat::Compat wrapper to be able to call
             // the futures 0.3 Future in a futures 0.1 context. Because
             // the Compat wrapper wants to to take ownership, the future
@@ -165,19 +537,84 @@ pub mod compat {
             let pollres = fut.poll();
             self.fut.replace(fut.into_inner());
             match pollres {
-                Ok(Async01::Ready(_)) => Ok(Async01::Ready(None)),
-                Ok(Async01::NotReady) => {
-                    let mut item = self.item.0.replace(None);
-                    if item.is_none() {
-                        Ok(Async01::NotReady)
-                    } else {
-                        Ok(Async01::Ready(item.take()))
+                // The future may have pushed its last item onto the queue
+                // in this same poll before returning Ready; drain that
+                // before treating Ready as end-of-stream, or it's lost.
+                // Mark the future done so it's never polled again, since
+                // it's not valid to call `poll` again once it's Ready.
+                Ok(Async01::Ready(_)) => {
+                    self.fut = None;
+                    match self.item.0.pop() {
+                        None => Ok(Async01::Ready(None)),
+                        Some(item) => Ok(Async01::Ready(Some(item))),
+                    }
+                }
+                Ok(Async01::NotReady) => match self.item.0.pop() {
+                    None => Ok(Async01::NotReady),
+                    Some(item) => Ok(Async01::Ready(Some(item))),
+                },
+                // Just like the `Ready` arm above, an item pushed onto the
+                // queue in the same poll that produced this error must be
+                // delivered before the stream is allowed to end; stash
+                // the error and return it on the next poll once the queue
+                // is empty.
+                Err(e) => {
+                    self.fut = None;
+                    match self.item.0.pop() {
+                        None => Err(e),
+                        Some(item) => {
+                            self.err = Some(e);
+                            Ok(Async01::Ready(Some(item)))
+                        }
                     }
                 }
-                Err(e) => Err(e),
             }
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use futures::executor::block_on;
+        use futures::StreamExt;
+        use futures01::future::err as err01;
+        use futures01::future::ok as ok01;
+        use futures01::stream::iter_ok as iter_ok01;
+        use futures01::stream::iter_result as iter_result01;
+
+        use super::super::AsyncStream;
+
+        #[test]
+        fn from_future01_yields_its_single_item() {
+            let strm = AsyncStream::<u8, ()>::from_future01(ok01(7u8));
+            let items: Vec<u8> = block_on(strm.map(|r| r.unwrap()).collect());
+            assert_eq!(items, vec![7]);
+        }
+
+        #[test]
+        fn from_future01_surfaces_its_error() {
+            let strm = AsyncStream::<u8, &'static str>::from_future01(err01("boom"));
+            let results: Vec<Result<u8, &'static str>> = block_on(strm.collect());
+            assert_eq!(results, vec![Err("boom")]);
+        }
+
+        #[test]
+        fn from_stream01_forwards_every_item() {
+            let strm = AsyncStream::<u8, ()>::from_stream01(iter_ok01(0u8..5));
+            let items: Vec<u8> = block_on(strm.map(|r| r.unwrap()).collect());
+            assert_eq!(items, (0u8..5).collect::<Vec<u8>>());
+        }
+
+        #[test]
+        fn from_stream01_ends_the_stream_with_its_error() {
+            let strm = AsyncStream::<u8, &'static str>::from_stream01(iter_result01(vec![
+                Ok(0u8),
+                Ok(1u8),
+                Err("boom"),
+            ]));
+            let results: Vec<Result<u8, &'static str>> = block_on(strm.collect());
+            assert_eq!(results, vec![Ok(0), Ok(1), Err("boom")]);
+        }
+    }
 }
 
 /// Stream implementation for Futures 0.3.
@@ -185,25 +622,240 @@ impl<I, E: Unpin> Stream03 for AsyncStream<I, E> {
     type Item = Result<I, E>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll03<Option<Result<I, E>>> {
+        // Drain the buffer before driving the producing future any
+        // further: this returns exactly one item per poll, so nothing
+        // already queued is overwritten or skipped, and it means a
+        // `Ready(Ok(_))` from the future is only trusted to end the
+        // stream once every buffered item has actually been handed out.
+        if let Some(item) = self.item.0.pop() {
+            return Poll03::Ready(Some(Ok(item)));
+        }
+        // The future already ran to completion (or errored) on an
+        // earlier poll, and its final item, if any, has since been
+        // drained above; surface the error it left behind, if any.
+        if self.fut.is_none() {
+            return match self.err.take() {
+                Some(e) => Poll03::Ready(Some(Err(e))),
+                None => Poll03::Ready(None),
+            };
+        }
+        if let Some(abort) = &self.abort {
+            abort.waker.register(cx.waker());
+            if abort.aborted.load(Ordering::SeqCst) {
+                self.fut = None;
+                return Poll03::Ready(None);
+            }
+        }
         let pollres = {
             let fut = self.fut.as_mut().unwrap();
             fut.as_mut().poll(cx)
         };
         match pollres {
-            // If the future returned Poll::Ready, that signals the end of the stream.
-            Poll03::Ready(Ok(_)) => Poll03::Ready(None),
-            Poll03::Ready(Err(e)) => Poll03::Ready(Some(Err(e))),
+            // The future may have pushed its last item onto the queue in
+            // this same poll before returning Ready, so that must be
+            // drained before the stream is allowed to end. Mark the
+            // future done so it's never polled again.
+            Poll03::Ready(Ok(_)) => {
+                self.fut = None;
+                match self.item.0.pop() {
+                    None => Poll03::Ready(None),
+                    Some(item) => Poll03::Ready(Some(Ok(item))),
+                }
+            }
+            // Just like the `Ok` arm above, an item pushed onto the queue
+            // in the same poll that produced this error must be
+            // delivered before the stream is allowed to end; stash the
+            // error and return it on the next poll once the queue is
+            // empty.
+            Poll03::Ready(Err(e)) => {
+                self.fut = None;
+                match self.item.0.pop() {
+                    None => Poll03::Ready(Some(Err(e))),
+                    Some(item) => {
+                        self.err = Some(e);
+                        Poll03::Ready(Some(Ok(item)))
+                    }
+                }
+            }
             Poll03::Pending => {
                 // Pending means that some sub-future returned pending. That sub-future
                 // _might_ have been the SenderFuture returned by Sender.send, so
-                // check if there is an item available in self.item.
-                let mut item = self.item.0.replace(None);
-                if item.is_none() {
-                    Poll03::Pending
-                } else {
-                    Poll03::Ready(Some(Ok(item.take().unwrap())))
+                // check if an item was pushed onto the queue while it was polled.
+                match self.item.0.pop() {
+                    None => Poll03::Pending,
+                    Some(item) => Poll03::Ready(Some(Ok(item))),
                 }
             }
         }
     }
 }
+
+/// Write a producing closure with `yield expr;` instead of
+/// `sender.send(expr).await`, and `?` to propagate errors.
+///
+/// See the [crate-level docs][crate] for an example.
+#[cfg(feature = "macros")]
+pub use async_stream_macros::stream;
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use std::task::Context;
+
+    use futures::executor::block_on;
+    use futures::task::noop_waker_ref;
+
+    use super::*;
+
+    #[test]
+    fn delivers_every_item_including_the_last() {
+        let mut strm = AsyncStream::<u8, ()>::new(move |mut y| async move {
+            for i in 0u8..10 {
+                y.send(i).await;
+            }
+            Ok(())
+        });
+
+        let items: Vec<u8> = block_on(async {
+            let mut out = Vec::new();
+            while let Some(item) = StreamExt03::next(&mut strm).await {
+                out.push(item.unwrap());
+            }
+            out
+        });
+
+        assert_eq!(items, (0u8..10).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn send_all_splices_in_every_item_of_the_inner_stream() {
+        let mut strm = AsyncStream::<u8, ()>::new(move |mut y| async move {
+            y.send(0u8).await;
+            y.send_all(futures::stream::iter(1u8..4).map(Ok)).await?;
+            y.send(4u8).await;
+            Ok(())
+        });
+
+        let items: Vec<u8> = block_on(async {
+            let mut out = Vec::new();
+            while let Some(item) = StreamExt03::next(&mut strm).await {
+                out.push(item.unwrap());
+            }
+            out
+        });
+
+        assert_eq!(items, (0u8..5).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn send_all_items_splices_in_a_plain_item_stream() {
+        let mut strm = AsyncStream::<u8, ()>::new(move |mut y| async move {
+            y.send(0u8).await;
+            y.send_all_items(futures::stream::iter(1u8..4)).await?;
+            y.send(4u8).await;
+            Ok(())
+        });
+
+        let items: Vec<u8> = block_on(async {
+            let mut out = Vec::new();
+            while let Some(item) = StreamExt03::next(&mut strm).await {
+                out.push(item.unwrap());
+            }
+            out
+        });
+
+        assert_eq!(items, (0u8..5).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn with_capacity_blocks_the_producer_until_the_consumer_catches_up() {
+        let sent = Arc::new(AtomicUsize::new(0));
+        let sent_in_closure = sent.clone();
+        let mut strm = AsyncStream::<usize, ()>::with_capacity(1, move |mut y| async move {
+            for i in 0usize..3 {
+                sent_in_closure.fetch_add(1, Ordering::SeqCst);
+                y.send(i).await;
+            }
+            Ok(())
+        });
+
+        let waker = noop_waker_ref();
+        let mut cx = Context::from_waker(waker);
+
+        // Nothing has been polled yet, so the producer hasn't run at all.
+        assert_eq!(sent.load(Ordering::SeqCst), 0);
+
+        let first = Pin::new(&mut strm).poll_next(&mut cx);
+        assert!(matches!(first, Poll03::Ready(Some(Ok(0)))));
+        // The single slot of capacity means the producer could only get
+        // as far as its second `send` (which is left pending) before the
+        // first item was handed back to us.
+        assert_eq!(sent.load(Ordering::SeqCst), 2);
+
+        let second = Pin::new(&mut strm).poll_next(&mut cx);
+        assert!(matches!(second, Poll03::Ready(Some(Ok(1)))));
+        assert_eq!(sent.load(Ordering::SeqCst), 3);
+
+        let third = Pin::new(&mut strm).poll_next(&mut cx);
+        assert!(matches!(third, Poll03::Ready(Some(Ok(2)))));
+
+        let done = Pin::new(&mut strm).poll_next(&mut cx);
+        assert!(matches!(done, Poll03::Ready(None)));
+    }
+
+    #[test]
+    fn abort_ends_the_stream_on_the_next_poll() {
+        let (mut strm, handle) = AsyncStream::<u8, ()>::abortable(move |mut y| async move {
+            loop {
+                y.send(0u8).await;
+            }
+        });
+
+        let waker = noop_waker_ref();
+        let mut cx = Context::from_waker(waker);
+
+        // The first poll hands back the first item; the producer's second
+        // `send` is left pending, so nothing is buffered at the abort.
+        let first = Pin::new(&mut strm).poll_next(&mut cx);
+        assert!(matches!(first, Poll03::Ready(Some(Ok(0)))));
+
+        handle.abort();
+
+        let done = Pin::new(&mut strm).poll_next(&mut cx);
+        assert!(matches!(done, Poll03::Ready(None)));
+    }
+
+    #[test]
+    fn an_item_sent_just_before_the_error_is_delivered_first() {
+        let mut strm = AsyncStream::<u8, &'static str>::new(move |mut y| async move {
+            y.send(0u8).await;
+            Err("boom")
+        });
+
+        let items: Vec<Result<u8, &'static str>> = block_on(async {
+            let mut out = Vec::new();
+            while let Some(item) = StreamExt03::next(&mut strm).await {
+                out.push(item);
+            }
+            out
+        });
+
+        assert_eq!(items, vec![Ok(0), Err("boom")]);
+    }
+
+    #[cfg(feature = "macros")]
+    #[test]
+    fn stream_macro_supports_yield_and_for_await() {
+        let strm = crate::stream! {
+            for i in 0u8..3 {
+                yield i;
+            }
+            for await j in futures::stream::iter(3u8..6).map(Ok::<u8, ()>) {
+                yield j;
+            }
+        };
+
+        let items: Vec<u8> = block_on(strm.map(|r| r.unwrap()).collect());
+        assert_eq!(items, (0u8..6).collect::<Vec<u8>>());
+    }
+}